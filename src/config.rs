@@ -1,10 +1,18 @@
 use envconfig::Envconfig;
+use serde::Deserialize;
 
 use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::process::Command;
+use std::str::FromStr;
 
 #[derive(Envconfig, Debug, Clone)]
 pub struct Config {
+    // ACCOUNT IDENTITY
+    #[envconfig(from = "IMAP_ACCOUNT_NAME", default = "default")]
+    pub name: String,
+
     // CONNECTION SETTINGS
     #[envconfig(from = "IMAP_HOST")]
     pub host: String,
@@ -12,15 +20,36 @@ pub struct Config {
     #[envconfig(from = "IMAP_PORT")]
     pub port: u16,
 
+    #[envconfig(from = "IMAP_TLS", default = "implicit")]
+    pub tls: TlsMode,
+
+    #[envconfig(from = "IMAP_AUTH", default = "plain")]
+    pub auth: AuthMode,
+
     #[envconfig(from = "IMAP_USER")]
     pub user: String,
 
     #[envconfig(from = "IMAP_PASSCMD")]
     pub pass_cmd: String,
 
+    // only used when `auth = xoauth2`; falls back to `pass_cmd` if empty, so
+    // a single command can mint a fresh bearer token.
+    #[envconfig(from = "IMAP_TOKENCMD", default = "")]
+    pub token_cmd: String,
+
     #[envconfig(from = "IMAP_MAILBOX", default = "INBOX")]
     pub mailbox: String,
 
+    // only used as a fallback when the server doesn't advertise IDLE.
+    #[envconfig(from = "IMAP_POLL_INTERVAL", default = "60")]
+    pub poll_interval: u64,
+
+    // how long to hold a single IDLE command open before sending DONE and
+    // re-issuing it. RFC 2177 recommends staying well under 30 minutes;
+    // values above that are clamped, see `Config::idle_refresh_interval`.
+    #[envconfig(from = "IMAP_IDLE_REFRESH", default = "1500")]
+    pub idle_refresh: u64,
+
     // SYNC SETTINGS
     #[envconfig(from = "IMAP_MAILDIR", default = "~/Maildir")]
     pub maildir: String,
@@ -30,32 +59,334 @@ pub struct Config {
 
     #[envconfig(from = "IMAP_MBSYNC_CONF", default = "")]
     pub mbsync_conf: String,
+
+    // NOTIFICATION BACKENDS
+    #[envconfig(from = "IMAP_NOTIFY_DESKTOP", default = "true")]
+    pub notify_desktop: bool,
+
+    #[envconfig(from = "IMAP_NOTIFY_SOUND", default = "true")]
+    pub notify_sound: bool,
+
+    // if empty, falls back to the embedded asset.
+    #[envconfig(from = "IMAP_NOTIFY_SOUND_PATH", default = "")]
+    pub notify_sound_path: String,
+
+    #[envconfig(from = "IMAP_NOTIFY_DBUS", default = "false")]
+    pub notify_dbus: bool,
+
+    #[envconfig(from = "IMAP_NOTIFY_DBUS_BUS_NAME", default = "net.ogbe.emacs")]
+    pub notify_dbus_bus_name: String,
+
+    #[envconfig(from = "IMAP_NOTIFY_DBUS_OBJECT_PATH", default = "/mail")]
+    pub notify_dbus_object_path: String,
+
+    #[envconfig(from = "IMAP_NOTIFY_DBUS_INTERFACE", default = "net.ogbe.emacs.mail")]
+    pub notify_dbus_interface: String,
+
+    // comma-separated list of methods to call, in order, on the same
+    // interface/object; defaults to the two calls the Emacs integration
+    // this tool was written for expects.
+    #[envconfig(from = "IMAP_NOTIFY_DBUS_METHOD", default = "reindex,refresh")]
+    pub notify_dbus_method: String,
+
+    // if non-empty, run this command on every new message, with
+    // NOTIFY_MAIL_FROM/NOTIFY_MAIL_SUBJECT/NOTIFY_MAIL_MAILBOX set.
+    #[envconfig(from = "IMAP_NOTIFY_EXEC", default = "")]
+    pub notify_exec: String,
 }
 
 impl Config {
-    /// Execute the given command to get the password.
+    /// Execute `pass_cmd` to get the account's password.
     pub fn get_password(&self) -> Result<String, Box<dyn Error>> {
-        // put together the command
-        let mut pass_cmd_it = self.pass_cmd.split(" ");
-        let cmd = match pass_cmd_it.next() {
-            Some(cmd) => cmd,
-            None => return Err("Error parsing pass_cmd".into()),
-        };
-        let mut command = Command::new(cmd);
-        for arg in pass_cmd_it {
-            command.arg(arg);
+        run_pass_cmd(self.pass_cmd.as_str())
+    }
+
+    /// Execute `token_cmd` (or `pass_cmd`, if `token_cmd` is unset) to get
+    /// the bearer token used for XOAUTH2.
+    pub fn get_token(&self) -> Result<String, Box<dyn Error>> {
+        if self.token_cmd.is_empty() {
+            run_pass_cmd(self.pass_cmd.as_str())
+        } else {
+            run_pass_cmd(self.token_cmd.as_str())
+        }
+    }
+
+    /// The configured IDLE refresh interval, clamped to 29 minutes per
+    /// RFC 2177 (servers are only required to tolerate IDLE for 30).
+    pub fn idle_refresh_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.idle_refresh.min(29 * 60))
+    }
+}
+
+impl From<AccountConfig> for Config {
+    fn from(account: AccountConfig) -> Self {
+        Self {
+            name: account.name,
+            host: account.host,
+            port: account.port,
+            tls: account.tls,
+            auth: account.auth,
+            user: account.user,
+            pass_cmd: account.pass_cmd,
+            token_cmd: account.token_cmd,
+            mailbox: account.mailbox,
+            poll_interval: account.poll_interval,
+            idle_refresh: account.idle_refresh,
+            maildir: account.maildir,
+            mbsync_path: account.mbsync_path,
+            mbsync_conf: account.mbsync_conf,
+            notify_desktop: account.notify_desktop,
+            notify_sound: account.notify_sound,
+            notify_sound_path: account.notify_sound_path,
+            notify_dbus: account.notify_dbus,
+            notify_dbus_bus_name: account.notify_dbus_bus_name,
+            notify_dbus_object_path: account.notify_dbus_object_path,
+            notify_dbus_interface: account.notify_dbus_interface,
+            notify_dbus_method: account.notify_dbus_method,
+            notify_exec: account.notify_exec,
         }
+    }
+}
+
+/// How to establish the transport-level security of the IMAP connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Connect straight over TLS (the historical behavior, e.g. port 993).
+    Implicit,
+    /// Connect in the clear, then upgrade via the `STARTTLS` command.
+    Starttls,
+    /// Never use TLS. Only useful against localhost/test servers.
+    None,
+}
 
-        // execute and return result
-        let output = command.output()?;
-        if !output.status.success() {
-            return Err(format!("Command exited with code: {:?}", output.status.code()).into());
+impl FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "implicit" => Ok(TlsMode::Implicit),
+            "starttls" => Ok(TlsMode::Starttls),
+            "none" => Ok(TlsMode::None),
+            other => Err(format!("Unknown tls mode: '{}'", other)),
         }
-        let s = std::str::from_utf8(&output.stdout)?;
-        Ok(String::from(s.strip_suffix("\n").unwrap()))
     }
 }
 
+impl fmt::Display for TlsMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TlsMode::Implicit => "implicit",
+            TlsMode::Starttls => "starttls",
+            TlsMode::None => "none",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Which authentication mechanism to use once connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Plain username/password login (the historical behavior).
+    Plain,
+    /// SASL XOAUTH2, using a bearer token from `token_cmd`/`pass_cmd`.
+    Xoauth2,
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(AuthMode::Plain),
+            "xoauth2" => Ok(AuthMode::Xoauth2),
+            other => Err(format!("Unknown auth mode: '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AuthMode::Plain => "plain",
+            AuthMode::Xoauth2 => "xoauth2",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Run a `pass_cmd`-style shell word list and return its stdout with the
+/// trailing newline removed.
+/// Shared between the single-account envconfig path and the multi-account
+/// TOML path so both get their password the same way.
+fn run_pass_cmd(pass_cmd: &str) -> Result<String, Box<dyn Error>> {
+    // put together the command
+    let mut pass_cmd_it = pass_cmd.split(" ");
+    let cmd = match pass_cmd_it.next() {
+        Some(cmd) => cmd,
+        None => return Err("Error parsing pass_cmd".into()),
+    };
+    let mut command = Command::new(cmd);
+    for arg in pass_cmd_it {
+        command.arg(arg);
+    }
+
+    // execute and return result
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(format!("Command exited with code: {:?}", output.status.code()).into());
+    }
+    let s = std::str::from_utf8(&output.stdout)?;
+    Ok(String::from(s.strip_suffix("\n").unwrap()))
+}
+
+/// A single account entry in the multi-account TOML config file. Mirrors
+/// `Config`, but every field that isn't strictly required comes with the
+/// same default as its envconfig counterpart.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccountConfig {
+    #[serde(default = "default_name")]
+    pub name: String,
+
+    pub host: String,
+    pub port: u16,
+
+    #[serde(default = "default_tls")]
+    pub tls: TlsMode,
+
+    #[serde(default = "default_auth")]
+    pub auth: AuthMode,
+
+    pub user: String,
+    pub pass_cmd: String,
+
+    #[serde(default)]
+    pub token_cmd: String,
+
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+
+    #[serde(default = "default_idle_refresh")]
+    pub idle_refresh: u64,
+
+    #[serde(default = "default_maildir")]
+    pub maildir: String,
+
+    #[serde(default = "default_mbsync_path")]
+    pub mbsync_path: String,
+
+    #[serde(default)]
+    pub mbsync_conf: String,
+
+    #[serde(default = "default_true")]
+    pub notify_desktop: bool,
+
+    #[serde(default = "default_true")]
+    pub notify_sound: bool,
+
+    #[serde(default)]
+    pub notify_sound_path: String,
+
+    #[serde(default)]
+    pub notify_dbus: bool,
+
+    #[serde(default = "default_dbus_bus_name")]
+    pub notify_dbus_bus_name: String,
+
+    #[serde(default = "default_dbus_object_path")]
+    pub notify_dbus_object_path: String,
+
+    #[serde(default = "default_dbus_interface")]
+    pub notify_dbus_interface: String,
+
+    #[serde(default = "default_dbus_method")]
+    pub notify_dbus_method: String,
+
+    #[serde(default)]
+    pub notify_exec: String,
+}
+
+fn default_name() -> String {
+    String::from("default")
+}
+
+fn default_tls() -> TlsMode {
+    TlsMode::Implicit
+}
+
+fn default_auth() -> AuthMode {
+    AuthMode::Plain
+}
+
+fn default_mailbox() -> String {
+    String::from("INBOX")
+}
+
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_idle_refresh() -> u64 {
+    1500
+}
+
+fn default_maildir() -> String {
+    String::from("~/Maildir")
+}
+
+fn default_mbsync_path() -> String {
+    String::from("mbsync")
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_dbus_bus_name() -> String {
+    String::from("net.ogbe.emacs")
+}
+
+fn default_dbus_object_path() -> String {
+    String::from("/mail")
+}
+
+fn default_dbus_interface() -> String {
+    String::from("net.ogbe.emacs.mail")
+}
+
+fn default_dbus_method() -> String {
+    String::from("reindex,refresh")
+}
+
+/// The top-level shape of the multi-account config file, e.g.:
+///
+/// ```toml
+/// [[account]]
+/// name = "work"
+/// host = "imap.example.com"
+/// port = 993
+/// user = "me@example.com"
+/// pass_cmd = "pass show work/imap"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+struct AccountsFile {
+    account: Vec<AccountConfig>,
+}
+
+/// Parse a list of accounts out of the TOML file at `path`.
+pub fn load_accounts(path: &str) -> Result<Vec<Config>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let parsed: AccountsFile = toml::from_str(&contents)?;
+    if parsed.account.is_empty() {
+        return Err(format!("No accounts found in {}", path).into());
+    }
+    Ok(parsed.account.into_iter().map(Config::from).collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,14 +410,54 @@ mod tests {
     fn test_config_defaults() {
         let config = get_default_config();
 
+        assert_eq!(config.name, "default");
         assert_eq!(config.host, "127.0.0.1");
         assert_eq!(config.port, 666);
+        assert_eq!(config.tls, TlsMode::Implicit);
+        assert_eq!(config.auth, AuthMode::Plain);
         assert_eq!(config.user, "user");
         assert_eq!(config.pass_cmd, "echo super_secret_password");
+        assert_eq!(config.token_cmd, "");
         assert_eq!(config.mailbox, "INBOX");
+        assert_eq!(config.poll_interval, 60);
+        assert_eq!(config.idle_refresh, 1500);
         assert_eq!(config.maildir, "~/Maildir");
         assert_eq!(config.mbsync_path, "mbsync");
         assert_eq!(config.mbsync_conf, "");
+        assert_eq!(config.notify_desktop, true);
+        assert_eq!(config.notify_sound, true);
+        assert_eq!(config.notify_sound_path, "");
+        assert_eq!(config.notify_dbus, false);
+        assert_eq!(config.notify_dbus_bus_name, "net.ogbe.emacs");
+        assert_eq!(config.notify_dbus_object_path, "/mail");
+        assert_eq!(config.notify_dbus_interface, "net.ogbe.emacs.mail");
+        assert_eq!(config.notify_dbus_method, "reindex,refresh");
+        assert_eq!(config.notify_exec, "");
+    }
+
+    /// test parsing the tls and auth mode env vars
+    #[test]
+    fn test_tls_and_auth_modes() {
+        assert_eq!("implicit".parse::<TlsMode>().unwrap(), TlsMode::Implicit);
+        assert_eq!("STARTTLS".parse::<TlsMode>().unwrap(), TlsMode::Starttls);
+        assert_eq!("none".parse::<TlsMode>().unwrap(), TlsMode::None);
+        assert!("bogus".parse::<TlsMode>().is_err());
+
+        assert_eq!("plain".parse::<AuthMode>().unwrap(), AuthMode::Plain);
+        assert_eq!("XOAUTH2".parse::<AuthMode>().unwrap(), AuthMode::Xoauth2);
+        assert!("bogus".parse::<AuthMode>().is_err());
+    }
+
+    /// test that idle_refresh_interval clamps to the RFC 2177 ceiling
+    #[test]
+    fn test_idle_refresh_interval_clamps() {
+        let mut config = get_default_config();
+
+        config.idle_refresh = 1500;
+        assert_eq!(config.idle_refresh_interval().as_secs(), 1500);
+
+        config.idle_refresh = 60 * 60;
+        assert_eq!(config.idle_refresh_interval().as_secs(), 29 * 60);
     }
 
     /// test the get_password function
@@ -98,4 +469,38 @@ mod tests {
 
         assert_eq!(password, "super_secret_password");
     }
+
+    /// test parsing a multi-account TOML file
+    #[test]
+    fn test_load_accounts() {
+        let dir = env::temp_dir().join("mail-notify-test-accounts.toml");
+        fs::write(
+            &dir,
+            r#"
+            [[account]]
+            name = "personal"
+            host = "imap.example.com"
+            port = 993
+            user = "me@example.com"
+            pass_cmd = "echo hunter2"
+
+            [[account]]
+            host = "imap.work.example.com"
+            port = 993
+            user = "me@work.example.com"
+            pass_cmd = "echo hunter3"
+            mailbox = "Work"
+            "#,
+        )
+        .unwrap();
+
+        let accounts = load_accounts(dir.to_str().unwrap()).unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].name, "personal");
+        assert_eq!(accounts[0].mailbox, "INBOX");
+        assert_eq!(accounts[1].name, "default");
+        assert_eq!(accounts[1].mailbox, "Work");
+
+        fs::remove_file(&dir).unwrap();
+    }
 }
@@ -4,15 +4,74 @@ use native_tls::{TlsConnector, TlsStream};
 use log::{debug, info, warn};
 
 use std::error::Error;
+use std::io::{self, Read, Write};
 use std::net::TcpStream;
 use std::thread;
 use std::time::Duration;
 
-use super::config::Config;
+use super::config::{AuthMode, Config, TlsMode};
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-pub type ImapSession = imap::Session<TlsStream<TcpStream>>;
+/// The underlying byte stream for an IMAP connection. Implicit-TLS and
+/// STARTTLS connections end up wrapping a `TlsStream`; cleartext connections
+/// use the raw `TcpStream`. Both live behind this one type so `ImapSession`
+/// stays a single concrete type regardless of which `tls` mode was chosen.
+pub enum MailStream {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+impl Read for MailStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MailStream::Tls(s) => s.read(buf),
+            MailStream::Plain(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MailStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MailStream::Tls(s) => s.write(buf),
+            MailStream::Plain(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MailStream::Tls(s) => s.flush(),
+            MailStream::Plain(s) => s.flush(),
+        }
+    }
+}
+
+impl imap::extensions::idle::SetReadTimeout for MailStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            MailStream::Tls(s) => s.get_ref().set_read_timeout(timeout),
+            MailStream::Plain(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+pub type ImapSession = imap::Session<MailStream>;
+
+/// A SASL XOAUTH2 authenticator built from the account's user name and a
+/// bearer token (see `Config::get_token`).
+struct Xoauth2 {
+    user: String,
+    token: String,
+}
+
+impl imap::Authenticator for Xoauth2 {
+    type Response = String;
+
+    fn process(&self, _challenge: &[u8]) -> Self::Response {
+        format!("user={}\x01auth=Bearer {}\x01\x01", self.user, self.token)
+    }
+}
 
 pub struct Connection {
     // need to keep the config to be able to re-compute
@@ -21,6 +80,10 @@ pub struct Connection {
     // this is public so that the handler can query the mail server for more
     // information.
     pub session: Option<ImapSession>,
+
+    // whether the server advertised IDLE support, decided once in connect()
+    // so wait() can branch without re-querying capabilities every time.
+    idle_supported: bool,
 }
 
 impl Connection {
@@ -30,34 +93,78 @@ impl Connection {
         Self {
             config: config.clone(),
             session: None,
+            idle_supported: true,
         }
     }
 
-    /// Connect to the mail server specified in the config.
+    /// Connect to the mail server specified in the config, using whichever
+    /// `tls` mode and `auth` mechanism it asks for.
     pub fn connect(&mut self) -> Result<()> {
-        debug!(
-            "Obtaining password with pass_cmd: '{}'",
-            self.config.pass_cmd
-        );
-        let password = self.config.get_password()?;
-
-        let tls = TlsConnector::builder().build()?;
-
-        debug!("Connecting to server");
-        let client = imap::connect(
-            (self.config.host.as_str(), self.config.port),
-            self.config.host.as_str(),
-            &tls,
-        )?;
-
-        debug!("Logging in");
-        let mut imap_session = client.login(&self.config.user, password).map_err(|e| e.0)?;
+        debug!("Connecting to {} via {}", self.config.host, self.config.tls);
+        let stream = self.open_stream()?;
+        let client = imap::Client::new(stream);
+
+        debug!("Authenticating via {}", self.config.auth);
+        let mut imap_session = match self.config.auth {
+            AuthMode::Plain => {
+                let password = self.config.get_password()?;
+                client
+                    .login(&self.config.user, password)
+                    .map_err(|e| e.0)?
+            }
+            AuthMode::Xoauth2 => {
+                let token = self.config.get_token()?;
+                let auth = Xoauth2 {
+                    user: self.config.user.clone(),
+                    token,
+                };
+                client.authenticate("XOAUTH2", &auth).map_err(|e| e.0)?
+            }
+        };
         imap_session.select(&self.config.mailbox)?;
+
+        let capabilities = imap_session.capabilities()?;
+        self.idle_supported = capabilities.has_str("IDLE");
+        if !self.idle_supported {
+            info!(
+                "Server does not advertise IDLE, falling back to polling every {}s",
+                self.config.poll_interval
+            );
+        }
+
         self.session = Some(imap_session);
 
         Ok(())
     }
 
+    /// Open the raw byte stream per `self.config.tls`: straight into TLS,
+    /// plaintext then upgraded via STARTTLS, or plaintext throughout.
+    fn open_stream(&self) -> Result<MailStream> {
+        let addr = (self.config.host.as_str(), self.config.port);
+
+        match self.config.tls {
+            TlsMode::Implicit => {
+                let tls = TlsConnector::builder().build()?;
+                let tcp = TcpStream::connect(addr)?;
+                Ok(MailStream::Tls(
+                    tls.connect(self.config.host.as_str(), tcp)?,
+                ))
+            }
+            TlsMode::Starttls => {
+                let tcp = TcpStream::connect(addr)?;
+                let client = imap::Client::new(tcp);
+                let capabilities = client.capabilities()?;
+                if !capabilities.has_str("STARTTLS") {
+                    return Err("Server does not advertise STARTTLS".into());
+                }
+                let tls = TlsConnector::builder().build()?;
+                let upgraded = client.secure(self.config.host.as_str(), &tls)?;
+                Ok(MailStream::Tls(upgraded.into_inner()))
+            }
+            TlsMode::None => Ok(MailStream::Plain(TcpStream::connect(addr)?)),
+        }
+    }
+
     fn logout(&mut self) {
         if let Some(session) = &mut self.session {
             let _ = session.logout();
@@ -115,14 +222,30 @@ impl Connection {
 
     /// Wait until the next update is received from the server. If anything
     /// goes wrong, return an error.
+    ///
+    /// When the server advertises IDLE, `wait_keepalive` bounds each IDLE to
+    /// `idle_refresh` (RFC 2177: servers may drop an IDLE after ~30 minutes
+    /// of silence): when that elapses it sends DONE and re-issues IDLE
+    /// itself, and only returns once the server reports mailbox activity,
+    /// so a single call here is enough — `listen()` then runs the handler,
+    /// whose UID check is a no-op if nothing new arrived. Without IDLE support we just
+    /// sleep for `poll_interval` and return, so `listen()` re-runs the
+    /// handler's UID check on every tick.
     fn wait(&mut self) -> Result<()> {
-        match &mut self.session {
-            Some(session) => {
-                let status = session.idle()?;
-                status.wait_keepalive()?; // blocks until something happens
-                Ok(())
-            }
-            None => Err("Not connected.".into()),
+        if !self.idle_supported {
+            thread::sleep(Duration::from_secs(self.config.poll_interval));
+            return match &self.session {
+                Some(_) => Ok(()),
+                None => Err("Not connected.".into()),
+            };
         }
+
+        let refresh_interval = self.config.idle_refresh_interval();
+        let session = self.session.as_mut().ok_or("Not connected.")?;
+
+        let mut idle = session.idle();
+        idle.set_keepalive(refresh_interval);
+        idle.wait_keepalive()?;
+        Ok(())
     }
 }
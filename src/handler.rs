@@ -1,18 +1,18 @@
+use std::borrow::Cow;
 use std::error::Error;
 use std::fs;
 use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 use dbus::blocking::Connection;
-use mailparse::{parse_headers, MailHeaderMap};
+use imap::types::Fetch;
 use notify_rust::{Notification, Timeout};
 use rodio::Source;
 use rust_embed::RustEmbed;
 use shellexpand;
 use sysinfo::{RefreshKind, SystemExt};
-use walkdir::WalkDir;
 
 use crate::config::Config;
 use crate::connection::ImapSession;
@@ -29,7 +29,7 @@ pub struct Handler {
 
 impl Handler {
     pub fn new(config: &Config) -> Result<Self> {
-        let notifier = Notifier::new(config.maildir.as_str(), config.mailbox.as_str())?;
+        let notifier = Notifier::new(config)?;
         let mbsync = Mbsync::new(config.mbsync_path.as_str(), config.mbsync_conf.as_str())?;
         Ok(Self {
             notifier,
@@ -45,8 +45,20 @@ impl Handler {
         // keep track of the last uid that we notified
         if let Some(uid) = latest_uid.into_iter().next() {
             if uid > self.last_notified {
+                // on the very first event we don't know how far back "new"
+                // goes, so only look at the newest message. afterwards we
+                // know exactly which UIDs arrived since last time.
+                let from_uid = if self.last_notified == 0 {
+                    uid
+                } else {
+                    self.last_notified + 1
+                };
+                self.sync_and_notify(session, from_uid, uid)?;
+                // only advance past this range once we've actually synced
+                // and notified for it; if sync_and_notify returns an error
+                // the reconnect loop retries and we must not have skipped
+                // these UIDs already.
                 self.last_notified = uid;
-                self.sync_and_notify();
             } else {
                 debug!("Got update, but already notified for this UID");
             }
@@ -55,102 +67,188 @@ impl Handler {
         Ok(())
     }
 
-    fn sync_and_notify(&self) {
-        if let Err(e) = self.mbsync.synchronize() {
-            panic!("Couldn't synchronize: {}", e);
-        }
-        if let Err(e) = self.notifier.notify() {
-            warn!("Error notifying: {:?}", e);
+    fn sync_and_notify(&self, session: &mut ImapSession, from_uid: u32, to_uid: u32) -> Result<()> {
+        // propagate mbsync failures instead of panicking: `handle()` runs
+        // inside `Connection::listen`, which already retries with backoff
+        // on an `Err`, so this gets the same reconnect treatment as any
+        // other transient failure instead of permanently killing the
+        // account's thread.
+        self.mbsync.synchronize()?;
+
+        let messages = fetch_new_messages(session, from_uid, to_uid)?;
+        for meta in messages {
+            if let Err(e) = self.notifier.notify(&meta) {
+                warn!("Error notifying: {:?}", e);
+            }
         }
+
+        Ok(())
     }
 }
 
+/// Fetch the `ENVELOPE` for every UID in `from_uid..=to_uid` and turn each
+/// one into a `MailMetadata`, straight from the server — no dependency on
+/// mbsync having already written the message to the maildir. A message
+/// we can't parse (e.g. no ENVELOPE) is logged and skipped rather than
+/// losing the notification for every other message in the batch.
+fn fetch_new_messages(session: &mut ImapSession, from_uid: u32, to_uid: u32) -> Result<Vec<MailMetadata>> {
+    let uid_set = format!("{}:{}", from_uid, to_uid);
+    let fetches = session.uid_fetch(uid_set, "ENVELOPE")?;
+    Ok(fetches
+        .iter()
+        .filter_map(|fetch| match MailMetadata::from_fetch(fetch) {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                warn!("Error parsing message {:?}: {:?}", fetch.uid, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// A single way of telling the user about a new message. Each backend is
+/// enabled and configured independently from `Config`, so a given setup
+/// might run all of them, one of them, or none.
+trait Notify {
+    fn fire(&self, meta: &MailMetadata) -> Result<()>;
+}
+
 struct Notifier {
-    path: PathBuf,
-    emacs: Emacs,
-    sound: SoundNotifier,
+    account: String,
+    backends: Vec<Box<dyn Notify>>,
 }
 
 impl Notifier {
-    pub fn new(maildir: &str, mailbox: &str) -> Result<Self> {
-        // get the right path for the maildir
-        let expanded = shellexpand::tilde(maildir).into_owned();
-        let path = Path::new(expanded.as_str()).join(mailbox);
-        let path = path.canonicalize()?;
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut backends: Vec<Box<dyn Notify>> = Vec::new();
 
-        // set up the emacs connection and the wav player
-        let emacs = Emacs::new()?;
-        let sound = SoundNotifier::new()?;
+        if config.notify_desktop {
+            backends.push(Box::new(DesktopNotify {
+                account: config.name.clone(),
+            }));
+        }
+        if config.notify_sound {
+            backends.push(Box::new(SoundNotify::new(
+                config.notify_sound_path.as_str(),
+            )?));
+        }
+        if config.notify_dbus {
+            backends.push(Box::new(DbusNotify {
+                bus_name: config.notify_dbus_bus_name.clone(),
+                object_path: config.notify_dbus_object_path.clone(),
+                interface: config.notify_dbus_interface.clone(),
+                methods: config
+                    .notify_dbus_method
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|m| !m.is_empty())
+                    .map(String::from)
+                    .collect(),
+            }));
+        }
+        if !config.notify_exec.is_empty() {
+            backends.push(Box::new(ExecNotify {
+                command: config.notify_exec.clone(),
+                mailbox: config.mailbox.clone(),
+            }));
+        }
 
-        Ok(Self { path, emacs, sound })
+        Ok(Self {
+            account: config.name.clone(),
+            backends,
+        })
     }
 
-    pub fn notify(&self) -> Result<()> {
-        info!("Got new mail, notifying...");
+    pub fn notify(&self, meta: &MailMetadata) -> Result<()> {
+        info!("Got new mail on account '{}', notifying...", self.account);
+
+        // let every backend have a go; one misbehaving backend (e.g. no
+        // D-Bus session, no sound card) shouldn't take the others down.
+        for backend in &self.backends {
+            if let Err(e) = backend.fire(meta) {
+                warn!("Notification backend failed: {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
 
-        // get the newest message from the maildir
-        let path = self
-            .get_newest_message()
-            .ok_or("Couldn't find most recent message!")?;
+struct DesktopNotify {
+    account: String,
+}
 
-        // send desktop notification
-        let MailMetadata { from, subject } = MailMetadata::new(path)?;
+impl Notify for DesktopNotify {
+    fn fire(&self, meta: &MailMetadata) -> Result<()> {
         Notification::new()
-            .summary(from.as_str())
-            .body(subject.as_str())
+            .summary(meta.from.as_str())
+            .body(meta.subject.as_str())
             .icon("mail-unread")
-            .appname("You've got mail!")
+            .appname(format!("You've got mail! ({})", self.account).as_str())
             .timeout(Timeout::Milliseconds(5000))
             .show()?;
+        Ok(())
+    }
+}
 
-        // notify emacs
-        self.emacs.notify()?;
-
-        // play audio
-        self.sound.play()?;
+/// Calls a list of user-configured D-Bus methods, in order, on the same
+/// bus name/object path/interface. Defaults to the two calls
+/// (`reindex`, `refresh`) the Emacs integration this tool was written for
+/// expects, but none of it is hardcoded anymore — `methods` comes straight
+/// from `Config::notify_dbus_method`, a comma-separated list.
+struct DbusNotify {
+    bus_name: String,
+    object_path: String,
+    interface: String,
+    methods: Vec<String>,
+}
 
+impl Notify for DbusNotify {
+    fn fire(&self, _meta: &MailMetadata) -> Result<()> {
+        let conn = Connection::new_session()?;
+        let proxy = conn.with_proxy(
+            self.bus_name.as_str(),
+            self.object_path.as_str(),
+            Duration::from_millis(5000),
+        );
+        for method in &self.methods {
+            debug!(
+                "Calling D-Bus method {}.{} on {} ({})",
+                self.interface, method, self.bus_name, self.object_path
+            );
+            let _ = proxy.method_call(self.interface.as_str(), method.as_str(), ())?;
+        }
         Ok(())
     }
+}
 
-    fn get_newest_message(&self) -> Option<PathBuf> {
-        let one_min_ago = SystemTime::now() - Duration::from_secs(60);
-
-        let mut files: Vec<_> = WalkDir::new(self.path.as_os_str())
-            .into_iter()
-            .filter_map(|e| match e {
-                // only look at files that I have permissions for. only add
-                // them if they are non-hidden plain files and are younger than a minute
-                // ago.
-                Ok(entry) if !is_hidden(&entry) => match entry.metadata() {
-                    Ok(md) if md.is_file() => match md.created() {
-                        Ok(time) if time >= one_min_ago => Some(entry),
-                        _ => None,
-                    },
-                    _ => None,
-                },
-                _ => None,
-            })
-            .collect();
-
-        // sort the remaining files to get the most recent
-        files.sort_by(|a, b| {
-            let a = a.metadata().unwrap().created().unwrap();
-            let b = b.metadata().unwrap().created().unwrap();
-            a.cmp(&b)
-        });
-
-        // return the path to the most recent if it exists. otherwise return
-        // None.
-        files.pop().and_then(|e| Some(e.into_path()))
-    }
+/// Runs an arbitrary user command, passing the message's From/Subject and
+/// the account's mailbox as environment variables, so users can wire up
+/// whatever they like without patching this tool.
+struct ExecNotify {
+    command: String,
+    mailbox: String,
 }
 
-fn is_hidden(entry: &walkdir::DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.starts_with("."))
-        .unwrap_or(false)
+impl Notify for ExecNotify {
+    fn fire(&self, meta: &MailMetadata) -> Result<()> {
+        let mut it = self.command.split(" ");
+        let cmd = it.next().ok_or("Error parsing notify_exec command")?;
+        let mut command = Command::new(cmd);
+        for arg in it {
+            command.arg(arg);
+        }
+        command
+            .env("NOTIFY_MAIL_FROM", meta.from.as_str())
+            .env("NOTIFY_MAIL_SUBJECT", meta.subject.as_str())
+            .env("NOTIFY_MAIL_MAILBOX", self.mailbox.as_str());
+
+        let status = command.status()?;
+        if !status.success() {
+            return Err(format!("notify_exec command exited with status: {:?}", status).into());
+        }
+        Ok(())
+    }
 }
 
 struct MailMetadata {
@@ -159,19 +257,55 @@ struct MailMetadata {
 }
 
 impl MailMetadata {
-    fn new(path: PathBuf) -> Result<Self> {
-        let contents = fs::read(path)?;
-        let (headers, _) = parse_headers(&contents)?;
-        let from = headers
-            .get_first_value("From")
-            .unwrap_or(String::from("Unknown Sender (parse error)"));
-        let subject = headers
-            .get_first_value("Subject")
-            .unwrap_or(String::from("Unknown Subject (parse error)"));
+    fn from_fetch(fetch: &Fetch) -> Result<Self> {
+        let envelope = fetch.envelope().ok_or("Message has no ENVELOPE")?;
+
+        let from = envelope
+            .from
+            .as_ref()
+            .and_then(|addrs| addrs.first())
+            .map(format_address)
+            .unwrap_or_else(|| String::from("Unknown Sender"));
+
+        let subject = envelope
+            .subject
+            .as_ref()
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .unwrap_or_else(|| String::from("Unknown Subject"));
+
         Ok(Self { from, subject })
     }
 }
 
+/// Render an ENVELOPE `Address` the way a mail client would: `Name
+/// <mailbox@host>` if we have a display name, `mailbox@host` otherwise.
+fn format_address(addr: &imap_proto::types::Address) -> String {
+    let mailbox = addr
+        .mailbox
+        .as_ref()
+        .map(|m| String::from_utf8_lossy(m).into_owned());
+    let host = addr
+        .host
+        .as_ref()
+        .map(|h| String::from_utf8_lossy(h).into_owned());
+
+    match (mailbox, host) {
+        (Some(mailbox), Some(host)) => {
+            let name = addr
+                .name
+                .as_ref()
+                .map(|n| String::from_utf8_lossy(n).into_owned())
+                .unwrap_or_default();
+            if name.is_empty() {
+                format!("{}@{}", mailbox, host)
+            } else {
+                format!("{} <{}@{}>", name, mailbox, host)
+            }
+        }
+        _ => String::from("Unknown Sender"),
+    }
+}
+
 struct Mbsync {
     command: String,
     config_path: Option<PathBuf>,
@@ -236,36 +370,34 @@ impl Mbsync {
     }
 }
 
-struct Emacs {}
-
-impl Emacs {
-    pub fn new() -> Result<Self> {
-        Ok(Self {})
-    }
-
-    /// use d-bus to notify Emacs
-    pub fn notify(&self) -> Result<()> {
-        debug!("Notifying Emacs");
-        let conn = Connection::new_session()?;
-        // I am registering these methods using some elisp code.
-        let proxy = conn.with_proxy("net.ogbe.emacs", "/mail", Duration::from_millis(5000));
-        let _ = proxy.method_call("net.ogbe.emacs.mail", "reindex", ())?;
-        let _ = proxy.method_call("net.ogbe.emacs.mail", "refresh", ())?;
-        Ok(())
-    }
-}
-
 // I use RustEmbed to save the wav notifications in the binary. I only have to
 // give the folder relative to the project root, and everything else works.
 #[derive(RustEmbed)]
 #[folder = "src/blob/"]
 struct Asset;
 
-struct SoundNotifier {}
+/// Plays a WAV file on every new message: a user-configured path, or the
+/// embedded asset if none is set.
+struct SoundNotify {
+    path: Option<PathBuf>,
+}
 
-impl SoundNotifier {
-    pub fn new() -> Result<Self> {
-        Ok(Self {})
+impl SoundNotify {
+    pub fn new(path: &str) -> Result<Self> {
+        let path = if path.is_empty() {
+            None
+        } else {
+            let expanded = shellexpand::tilde(path).into_owned();
+            Some(Path::new(expanded.as_str()).canonicalize()?)
+        };
+        Ok(Self { path })
+    }
+
+    fn bytes(&self) -> Result<Cow<'static, [u8]>> {
+        match &self.path {
+            Some(path) => Ok(Cow::Owned(fs::read(path)?)),
+            None => Asset::get("snd1.wav").ok_or_else(|| "Couldn't get sound asset".into()),
+        }
     }
 
     // I would like to use pure rust library like rodio here, but
@@ -276,19 +408,18 @@ impl SoundNotifier {
     // https://github.com/RustAudio/rodio/issues/299
     pub fn _play_rodio(&self) -> Result<()> {
         let device = rodio::default_output_device().ok_or("Couldn't find output device!")?;
-        // I wonder whether I can cache some of these below operations? I
-        // attempted to store some of the intermediate things (the cursor, the
-        // src, etc. in the SoundNotifier struct, but ran into a whole bunch of
-        // problems.
-        let bytes = Asset::get("snd1.wav").ok_or("Couldn't get sound asset")?;
+        let bytes = self.bytes()?;
         let cursor = Cursor::new(bytes);
         let src = rodio::Decoder::new(cursor)?;
         rodio::play_raw(&device, src.convert_samples());
         Ok(())
     }
+}
 
-    pub fn _play_aplay(&self) -> Result<()> {
-        let bytes = Asset::get("snd1.wav").ok_or("Couldn't get sound asset")?;
+impl Notify for SoundNotify {
+    fn fire(&self, _meta: &MailMetadata) -> Result<()> {
+        debug!("Playing sound");
+        let bytes = self.bytes()?;
         let mut proc = Command::new("aplay")
             .arg("-")
             .stdin(Stdio::piped())
@@ -301,9 +432,51 @@ impl SoundNotifier {
         proc.wait()?;
         Ok(())
     }
+}
 
-    pub fn play(&self) -> Result<()> {
-        debug!("Playing sound");
-        self._play_aplay()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_proto::types::Address;
+
+    fn addr(
+        name: Option<&'static str>,
+        mailbox: Option<&'static str>,
+        host: Option<&'static str>,
+    ) -> Address<'static> {
+        Address {
+            name: name.map(|s| Cow::Borrowed(s.as_bytes())),
+            adl: None,
+            mailbox: mailbox.map(|s| Cow::Borrowed(s.as_bytes())),
+            host: host.map(|s| Cow::Borrowed(s.as_bytes())),
+        }
+    }
+
+    /// test formatting an address with a display name
+    #[test]
+    fn test_format_address_with_name() {
+        let a = addr(Some("Alice"), Some("alice"), Some("example.com"));
+        assert_eq!(format_address(&a), "Alice <alice@example.com>");
+    }
+
+    /// test formatting an address with no display name
+    #[test]
+    fn test_format_address_no_name() {
+        let a = addr(None, Some("alice"), Some("example.com"));
+        assert_eq!(format_address(&a), "alice@example.com");
+    }
+
+    /// test formatting an address missing the host part
+    #[test]
+    fn test_format_address_no_host() {
+        let a = addr(Some("Alice"), Some("alice"), None);
+        assert_eq!(format_address(&a), "Unknown Sender");
+    }
+
+    /// test formatting an address missing the mailbox part
+    #[test]
+    fn test_format_address_no_mailbox() {
+        let a = addr(Some("Alice"), None, Some("example.com"));
+        assert_eq!(format_address(&a), "Unknown Sender");
     }
 }
@@ -3,6 +3,7 @@ extern crate envconfig_derive;
 extern crate envconfig;
 use envconfig::Envconfig;
 mod config;
+use config::Config;
 
 extern crate pretty_env_logger;
 #[macro_use]
@@ -14,37 +15,76 @@ use connection::Connection;
 mod handler;
 use handler::Handler;
 
+use std::env;
 use std::process;
+use std::thread;
 
 fn main() {
     pretty_env_logger::init();
 
-    info!("Loading config");
-    let conf = config::Config::init().unwrap_or_else(|err| {
-        warn!("Error initializing config: {:?}", err);
-        process::exit(1);
-    });
-    debug!("Loaded configuration: {:?}", conf);
+    let accounts = load_accounts();
 
-    info!("Connecting to server");
+    info!("Starting {} account(s)", accounts.len());
+    let threads: Vec<_> = accounts
+        .into_iter()
+        .map(|conf| thread::spawn(move || run_account(conf)))
+        .collect();
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+/// Figure out which accounts to monitor. If `IMAP_CONFIG_FILE` is set, load a
+/// TOML file that may describe several accounts. Otherwise fall back to the
+/// single account described by the `IMAP_*` envconfig variables.
+fn load_accounts() -> Vec<Config> {
+    match env::var("IMAP_CONFIG_FILE") {
+        Ok(path) => {
+            info!("Loading accounts from config file: {}", path);
+            config::load_accounts(path.as_str()).unwrap_or_else(|err| {
+                warn!("Error loading config file: {:?}", err);
+                process::exit(1);
+            })
+        }
+        Err(_) => {
+            info!("IMAP_CONFIG_FILE not set, loading a single account from the environment");
+            let conf = Config::init().unwrap_or_else(|err| {
+                warn!("Error initializing config: {:?}", err);
+                process::exit(1);
+            });
+            vec![conf]
+        }
+    }
+}
+
+/// Monitor a single account: connect, set up its handler, and listen for
+/// updates forever (or until the reconnect loop gives up). Errors here only
+/// stop this account's thread — a bad account must never take the others
+/// down with it, so we log and return instead of exiting the process.
+fn run_account(conf: Config) {
+    debug!("[{}] Loaded configuration: {:?}", conf.name, conf);
+
+    info!("[{}] Connecting to server", conf.name);
     let mut connection = Connection::new(&conf);
 
-    connection.connect().unwrap_or_else(|err| {
-        warn!("Error connecting to server: {:?}", err);
-        process::exit(1);
-    });
-    info!("Successfully connected");
-
-    debug!("Setting up handler");
-    let mut handler = Handler::new(&conf).unwrap_or_else(|err| {
-        warn!("Error creating handler: {:?}", err);
-        process::exit(1);
-    });
-
-    info!("Listening for updates");
-    connection
-        .listen(move |c| handler.handle(c))
-        .unwrap_or_else(|err| {
-            warn!("Error while listening for updates: {}", err);
-        });
+    if let Err(err) = connection.connect() {
+        warn!("[{}] Error connecting to server: {:?}", conf.name, err);
+        return;
+    }
+    info!("[{}] Successfully connected", conf.name);
+
+    debug!("[{}] Setting up handler", conf.name);
+    let mut handler = match Handler::new(&conf) {
+        Ok(handler) => handler,
+        Err(err) => {
+            warn!("[{}] Error creating handler: {:?}", conf.name, err);
+            return;
+        }
+    };
+
+    info!("[{}] Listening for updates", conf.name);
+    if let Err(err) = connection.listen(move |c| handler.handle(c)) {
+        warn!("[{}] Error while listening for updates: {}", conf.name, err);
+    }
 }